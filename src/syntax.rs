@@ -1,19 +1,73 @@
 use crate::symbol::Symbol;
 use anyhow::{anyhow, Result};
 
+/// Half-open byte range `[start, end)` into the original source text that a
+/// [`Symbol`] was lexed from. Carried alongside every symbol so diagnostics can
+/// point back at the exact character the user typed.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
 struct Source<'a> {
+    src: &'a str,
     code: &'a [Symbol],
+    spans: &'a [Span],
     cursor: usize,
+    diagnostics: Vec<Diagnostic>,
 }
 
 impl<'a> Source<'a> {
-    fn new(code: &'a [Symbol]) -> Self {
-        Self { code, cursor: 0 }
+    fn new(src: &'a str, code: &'a [Symbol], spans: &'a [Span]) -> Self {
+        Self {
+            src,
+            code,
+            spans,
+            cursor: 0,
+            diagnostics: Vec::new(),
+        }
     }
-    fn next_symbol(&mut self) -> Symbol {
-        let sym = self.code.get(self.cursor).or(Some(&Symbol::EoF)).unwrap();
+    fn next_symbol(&mut self) -> (Symbol, Span) {
+        let sym = self.code.get(self.cursor).copied().unwrap_or(Symbol::EoF);
+        let span = self.spans.get(self.cursor).copied().unwrap_or_else(|| {
+            let end = self.src.len();
+            Span { start: end, end }
+        });
         self.cursor += 1;
-        *sym
+        (sym, span)
+    }
+    /// Render `msg` as a rustc-style diagnostic anchored at `span`: a
+    /// `line, col` location followed by the offending source line and a caret.
+    fn render(&self, span: Span, msg: impl std::fmt::Display) -> String {
+        let (line, col) = self.line_col(span.start);
+        let line_text = self.src.lines().nth(line - 1).unwrap_or("");
+        let caret = format!("{}^", " ".repeat(col.saturating_sub(1)));
+        format!("{msg} at line {line}, col {col}\n{line_text}\n{caret}")
+    }
+    fn error(&self, span: Span, msg: impl std::fmt::Display) -> anyhow::Error {
+        anyhow!("{}", self.render(span, msg))
+    }
+    /// Record a recoverable error and continue parsing instead of aborting.
+    fn diagnostic(&mut self, span: Span, msg: impl std::fmt::Display) {
+        let message = self.render(span, msg);
+        self.diagnostics.push(Diagnostic { message });
+    }
+    fn line_col(&self, offset: usize) -> (usize, usize) {
+        let mut line = 1;
+        let mut col = 1;
+        for (i, c) in self.src.char_indices() {
+            if i >= offset {
+                break;
+            }
+            if c == '\n' {
+                line += 1;
+                col = 1;
+            } else {
+                col += 1;
+            }
+        }
+        (line, col)
     }
     fn snapshot(&self) -> Snapshot {
         self.cursor
@@ -26,109 +80,444 @@ impl<'a> Source<'a> {
 type Snapshot = usize;
 type Program = Vec<Expression>;
 
+/// Marks whether a node was produced by error recovery rather than from
+/// well-formed input. As in rustc's parser, a node is only ever `Yes` when at
+/// least one [`Diagnostic`] was recorded, so error-flagged output always comes
+/// with an explanation.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Recovered {
+    No,
+    Yes,
+}
+
+/// A recoverable parse error, already rendered against its source location.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Diagnostic {
+    pub message: String,
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 enum Expression {
-    Loop(Vec<Expression>),
+    Loop(Vec<Expression>, Recovered),
     Operator(Symbol),
 }
 
-pub fn parser(program: &[Symbol]) -> Result<Program> {
-    let source = &mut Source::new(program);
+pub fn parser(src: &str, program: &[Symbol], spans: &[Span]) -> (Program, Vec<Diagnostic>) {
+    let source = &mut Source::new(src, program, spans);
     let mut result = Vec::new();
     loop {
         match parse(source, exp) {
             Ok(exp) => {
                 result.push(exp);
             }
-            Err(e) => {
-                return if source.next_symbol() == Symbol::EoF {
-                    Ok(result)
-                } else {
-                    Err(e)
-                };
+            Err(_) => {
+                let (symbol, span) = source.next_symbol();
+                if symbol == Symbol::EoF {
+                    break;
+                }
+                // A stray `]` (or other unparseable symbol) at top level: report
+                // it and skip past it so the rest of the program still parses.
+                source.diagnostic(span, format!("unexpected {:?}", symbol));
             }
         }
     }
+    (result, std::mem::take(&mut source.diagnostics))
 }
 
 fn exp(program: &mut Source) -> Result<Expression> {
-    let lp_result = parse(program, lp);
-    let lp_err = if let Err(err) = lp_result {
-        err
-    } else {
-        return lp_result;
-    };
+    alt(program, &[lp, sym])
+}
 
-    let sym_result = parse(program, sym);
-    let sym_err = if let Err(err) = sym_result {
-        err
-    } else {
-        return sym_result;
-    };
+fn exp_list(program: &mut Source) -> Result<Vec<Expression>> {
+    many1(program, exp)
+}
 
-    Err(anyhow!("parse error: ").context(lp_err).context(sym_err))
+fn lp(program: &mut Source) -> Result<Expression> {
+    let (body, recovered) = between(program, Symbol::LeftBracket, exp_list, Symbol::RightBracket)?;
+    Ok(Expression::Loop(body, recovered))
 }
 
-fn exp_list(program: &mut Source) -> Result<Vec<Expression>> {
-    let mut result = Vec::new();
-    while let Ok(exp) = parse(program, exp) {
-        result.push(exp);
+fn sym(program: &mut Source) -> Result<Expression> {
+    let (symbol, span) = program.next_symbol();
+    match symbol {
+        Symbol::RightBracket | Symbol::LeftBracket | Symbol::EoF => {
+            Err(program.error(span, format!("unexpected {:?}", symbol)))
+        }
+        _ => Ok(Expression::Operator(symbol)),
     }
-    if result.is_empty() {
-        Err(anyhow!("Expect at least one expression"))
-    } else {
-        Ok(result)
+}
+
+/// A flat, optimized instruction after lowering the [`Expression`] AST.
+///
+/// `Add`/`Move` carry the *net* effect of a run of operators, so a compact
+/// stream here runs far faster than walking the raw tree.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Instruction {
+    Add(i8),
+    Move(i32),
+    Output,
+    Input,
+    Loop(Vec<Instruction>),
+    SetZero,
+}
+
+/// Lower a parsed [`Program`] into an optimized [`Instruction`] stream.
+///
+/// Three classic Brainfuck optimizations happen during the single walk:
+/// consecutive `+`/`-` fold into one [`Instruction::Add`] and consecutive
+/// `>`/`<` into one [`Instruction::Move`] (runs netting to zero are dropped);
+/// a loop whose body is a lone `+`/`-` becomes [`Instruction::SetZero`]; any
+/// other loop is lowered recursively.
+pub fn lower(program: &Program) -> Vec<Instruction> {
+    lower_body(program)
+}
+
+fn lower_body(exprs: &[Expression]) -> Vec<Instruction> {
+    let mut out = Vec::new();
+    let mut add: i64 = 0;
+    let mut mv: i64 = 0;
+    for expr in exprs {
+        match expr {
+            Expression::Operator(Symbol::PlusOne) => {
+                flush_move(&mut out, &mut mv);
+                add += 1;
+            }
+            Expression::Operator(Symbol::MinusOne) => {
+                flush_move(&mut out, &mut mv);
+                add -= 1;
+            }
+            Expression::Operator(Symbol::Forward) => {
+                flush_add(&mut out, &mut add);
+                mv += 1;
+            }
+            Expression::Operator(Symbol::Backward) => {
+                flush_add(&mut out, &mut add);
+                mv -= 1;
+            }
+            Expression::Operator(Symbol::Output) => {
+                flush_add(&mut out, &mut add);
+                flush_move(&mut out, &mut mv);
+                out.push(Instruction::Output);
+            }
+            Expression::Operator(Symbol::Input) => {
+                flush_add(&mut out, &mut add);
+                flush_move(&mut out, &mut mv);
+                out.push(Instruction::Input);
+            }
+            Expression::Loop(body, _) => {
+                flush_add(&mut out, &mut add);
+                flush_move(&mut out, &mut mv);
+                out.push(if is_clear_loop(body) {
+                    Instruction::SetZero
+                } else {
+                    Instruction::Loop(lower_body(body))
+                });
+            }
+            // Brackets and EoF never reach the AST as operators.
+            Expression::Operator(_) => {}
+        }
     }
+    flush_add(&mut out, &mut add);
+    flush_move(&mut out, &mut mv);
+    out
 }
 
-fn lp(program: &mut Source) -> Result<Expression> {
-    let symbol = program.next_symbol();
-    if Symbol::LeftBracket != symbol {
-        return Err(anyhow!("Expect left bracket, but got {:?}", symbol));
+fn flush_add(out: &mut Vec<Instruction>, add: &mut i64) {
+    if *add != 0 {
+        out.push(Instruction::Add(*add as i8));
+        *add = 0;
     }
+}
+
+fn flush_move(out: &mut Vec<Instruction>, mv: &mut i64) {
+    if *mv != 0 {
+        out.push(Instruction::Move(*mv as i32));
+        *mv = 0;
+    }
+}
 
-    let exp_list = parse(program, exp_list)?;
+/// A `[-]` / `[+]` loop zeroes the current cell regardless of its value.
+fn is_clear_loop(body: &[Expression]) -> bool {
+    matches!(
+        body,
+        [Expression::Operator(Symbol::PlusOne)] | [Expression::Operator(Symbol::MinusOne)]
+    )
+}
+
+/// Serialize a [`Program`] back into the symbol stream a parser would consume,
+/// bracketing every loop body. For a well-formed `p`, `parser` run over
+/// `unparse(&p)` yields `p` again with no diagnostics.
+pub fn unparse(program: &Program) -> Vec<Symbol> {
+    let mut out = Vec::new();
+    unparse_into(program, &mut out);
+    out
+}
 
-    let symbol = program.next_symbol();
-    if Symbol::RightBracket != symbol {
-        return Err(anyhow!("Expect right bracket, but got {:?}", symbol));
+fn unparse_into(exprs: &[Expression], out: &mut Vec<Symbol>) {
+    for expr in exprs {
+        match expr {
+            Expression::Operator(symbol) => out.push(*symbol),
+            Expression::Loop(body, _) => {
+                out.push(Symbol::LeftBracket);
+                unparse_into(body, out);
+                out.push(Symbol::RightBracket);
+            }
+        }
     }
-    Ok(Expression::Loop(exp_list))
 }
 
-fn sym(program: &mut Source) -> Result<Expression> {
-    let symbol = program.next_symbol();
+/// Render a [`Program`] as its canonical Brainfuck characters — a formatter and
+/// a way to emit normalized programs.
+pub fn to_string(program: &Program) -> String {
+    unparse(program).iter().map(|s| symbol_char(*s)).collect()
+}
+
+fn symbol_char(symbol: Symbol) -> char {
     match symbol {
-        Symbol::RightBracket | Symbol::LeftBracket | Symbol::EoF => {
-            Err(anyhow!("Expect symbols, but got {:?}", symbol))
-        }
-        _ => Ok(Expression::Operator(symbol)),
+        Symbol::PlusOne => '+',
+        Symbol::MinusOne => '-',
+        Symbol::Forward => '>',
+        Symbol::Backward => '<',
+        Symbol::Output => '.',
+        Symbol::Input => ',',
+        Symbol::LeftBracket => '[',
+        Symbol::RightBracket => ']',
+        Symbol::EoF => '\0',
     }
 }
 
+/// Run a single `rule`, restoring the cursor if it fails. The snapshot/restore
+/// primitive the rest of the combinator layer is built on.
 fn parse<T>(program: &mut Source, rule: fn(&mut Source) -> Result<T>) -> Result<T> {
     let snapshot = program.snapshot();
-    if let Ok(exp) = rule(program) {
-        return Ok(exp);
+    match rule(program) {
+        Ok(exp) => Ok(exp),
+        Err(e) => {
+            program.restore(snapshot);
+            Err(e)
+        }
+    }
+}
+
+/// Ordered choice: try each `rule` in turn, restoring between attempts, and
+/// return the first success. If every rule fails, the errors are chained so the
+/// caller can see why each alternative was rejected.
+fn alt<T>(program: &mut Source, rules: &[fn(&mut Source) -> Result<T>]) -> Result<T> {
+    let mut last: Option<anyhow::Error> = None;
+    for rule in rules {
+        match parse(program, *rule) {
+            Ok(value) => return Ok(value),
+            Err(err) => {
+                last = Some(match last {
+                    Some(prev) => err.context(prev),
+                    None => err,
+                });
+            }
+        }
+    }
+    Err(last.unwrap_or_else(|| anyhow!("no alternative matched")))
+}
+
+/// One-or-more: apply `rule` repeatedly until it fails, requiring at least one
+/// match.
+fn many1<T>(program: &mut Source, rule: fn(&mut Source) -> Result<T>) -> Result<Vec<T>> {
+    let mut result = Vec::new();
+    while let Ok(item) = parse(program, rule) {
+        result.push(item);
+    }
+    if result.is_empty() {
+        Err(anyhow!("expected at least one match"))
+    } else {
+        Ok(result)
+    }
+}
+
+/// Consume the single symbol `expected`, returning its [`Span`] for later use in
+/// diagnostics.
+fn expect(program: &mut Source, expected: Symbol) -> Result<Span> {
+    let (symbol, span) = program.next_symbol();
+    if symbol == expected {
+        Ok(span)
+    } else {
+        Err(program.error(span, format!("expected '{}', but got {:?}", symbol_char(expected), symbol)))
+    }
+}
+
+/// Parse `body` delimited by the `open`/`close` symbols. If `close` is missing
+/// (EoF before the matching delimiter) the close is synthesized, a diagnostic is
+/// recorded against the opening symbol, and the body is reported as
+/// [`Recovered::Yes`].
+fn between<T>(
+    program: &mut Source,
+    open: Symbol,
+    body: fn(&mut Source) -> Result<T>,
+    close: Symbol,
+) -> Result<(T, Recovered)> {
+    let open_span = expect(program, open)?;
+    let inner = body(program)?;
+    let (symbol, span) = program.next_symbol();
+    if symbol == close {
+        Ok((inner, Recovered::No))
+    } else if symbol == Symbol::EoF {
+        program.diagnostic(open_span, format!("unmatched '{}'", symbol_char(open)));
+        Ok((inner, Recovered::Yes))
+    } else {
+        Err(program.error(span, format!("expected '{}', but got {:?}", symbol_char(close), symbol)))
     }
-    program.restore(snapshot);
-    Err(anyhow!("parse error"))
 }
 
 #[cfg(test)]
 mod syntax {
     use super::*;
     use crate::symbol::Symbol::*;
+
+    /// Build contiguous single-byte spans for a symbol slice, mirroring what a
+    /// lexer over one-character tokens would emit.
+    fn spans_for(code: &[Symbol]) -> Vec<Span> {
+        (0..code.len())
+            .map(|i| Span {
+                start: i,
+                end: i + 1,
+            })
+            .collect()
+    }
+
     #[test]
     fn test_parser() {
         let code = vec![LeftBracket, PlusOne, RightBracket, MinusOne];
-        let program = parser(&code);
+        let spans = spans_for(&code);
+        let (program, diagnostics) = parser("[+]-", &code, &spans);
+        assert!(diagnostics.is_empty());
+        assert_eq!(
+            program,
+            vec![
+                Expression::Loop(vec![Expression::Operator(PlusOne)], Recovered::No),
+                Expression::Operator(MinusOne),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unclosed_loop_recovers() {
+        let code = vec![LeftBracket, PlusOne];
+        let spans = spans_for(&code);
+        let (program, diagnostics) = parser("[+", &code, &spans);
         assert_eq!(
-            program.unwrap_or_else(|e| panic!("Error: {}", e)),
+            program,
+            vec![Expression::Loop(
+                vec![Expression::Operator(PlusOne)],
+                Recovered::Yes
+            )]
+        );
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].message.contains("unmatched '['"));
+    }
+
+    #[test]
+    fn test_stray_right_bracket_is_skipped() {
+        let code = vec![PlusOne, RightBracket, MinusOne];
+        let spans = spans_for(&code);
+        let (program, diagnostics) = parser("+]-", &code, &spans);
+        assert_eq!(
+            program,
             vec![
-                Expression::Loop(vec![Expression::Operator(PlusOne)]),
+                Expression::Operator(PlusOne),
                 Expression::Operator(MinusOne),
             ]
         );
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].message.contains("unexpected"));
+    }
+
+    #[test]
+    fn test_lower_folds_runs() {
+        let program = vec![
+            Expression::Operator(PlusOne),
+            Expression::Operator(PlusOne),
+            Expression::Operator(MinusOne),
+            Expression::Operator(Forward),
+            Expression::Operator(Forward),
+        ];
+        assert_eq!(lower(&program), vec![Instruction::Add(1), Instruction::Move(2)]);
+    }
+
+    #[test]
+    fn test_lower_drops_zero_net_runs() {
+        let program = vec![
+            Expression::Operator(PlusOne),
+            Expression::Operator(MinusOne),
+            Expression::Operator(Output),
+        ];
+        assert_eq!(lower(&program), vec![Instruction::Output]);
+    }
+
+    #[test]
+    fn test_lower_recognizes_clear_loop() {
+        let program = vec![Expression::Loop(
+            vec![Expression::Operator(MinusOne)],
+            Recovered::No,
+        )];
+        assert_eq!(lower(&program), vec![Instruction::SetZero]);
+    }
+
+    #[test]
+    fn test_lower_keeps_other_loops_recursive() {
+        let program = vec![Expression::Loop(
+            vec![
+                Expression::Operator(Forward),
+                Expression::Operator(PlusOne),
+            ],
+            Recovered::No,
+        )];
+        assert_eq!(
+            lower(&program),
+            vec![Instruction::Loop(vec![Instruction::Move(1), Instruction::Add(1)])]
+        );
+    }
+
+    /// Minimal deterministic LCG — lets the round-trip property run over many
+    /// generated programs without pulling in an external generator crate.
+    struct Rng(u64);
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            self.0 >> 33
+        }
+    }
+
+    fn gen_program(rng: &mut Rng, depth: u32) -> Vec<Expression> {
+        const OPS: [Symbol; 6] = [PlusOne, MinusOne, Forward, Backward, Output, Input];
+        let len = (rng.next() % 5) as usize;
+        let mut exprs = Vec::with_capacity(len);
+        for _ in 0..len {
+            if depth > 0 && rng.next() % 4 == 0 {
+                let mut body = gen_program(rng, depth - 1);
+                // Empty loop bodies aren't well-formed — `exp_list` needs one.
+                if body.is_empty() {
+                    body.push(Expression::Operator(OPS[(rng.next() % 6) as usize]));
+                }
+                exprs.push(Expression::Loop(body, Recovered::No));
+            } else {
+                exprs.push(Expression::Operator(OPS[(rng.next() % 6) as usize]));
+            }
+        }
+        exprs
+    }
+
+    #[test]
+    fn test_unparse_round_trip() {
+        let mut rng = Rng(0x1234_5678_9abc_def0);
+        for _ in 0..200 {
+            let program = gen_program(&mut rng, 3);
+            let code = unparse(&program);
+            let spans = spans_for(&code);
+            let src = to_string(&program);
+            let (reparsed, diagnostics) = parser(&src, &code, &spans);
+            assert!(diagnostics.is_empty());
+            assert_eq!(reparsed, program);
+        }
     }
-}
\ No newline at end of file
+}